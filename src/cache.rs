@@ -0,0 +1,371 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    hash::{Hash, Hasher},
+};
+
+use raug_graph::graph::NodeIndex;
+use raug_server::graph::{MeterKind, NameOrIndex};
+use tokio::sync::Mutex;
+
+/// By-hash cache with touched/orphan-drain bookkeeping, shared by
+/// [`NodeCache`] and [`SubscriptionCache`]: `get`/`insert` mark an entry
+/// touched, and `drain_orphans` forgets and returns whatever wasn't
+/// touched since the last drain (and that `protect` doesn't want spared
+/// anyway), then resets the touched set for the next evaluation.
+struct TouchedCache<T> {
+    by_hash: Mutex<HashMap<u64, T>>,
+    touched: Mutex<HashSet<T>>,
+}
+
+impl<T> Default for TouchedCache<T> {
+    fn default() -> Self {
+        Self {
+            by_hash: Mutex::new(HashMap::new()),
+            touched: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl<T: Copy + Eq + Hash> TouchedCache<T> {
+    /// Looks `hash` up, marking it touched on a hit.
+    async fn get(&self, hash: u64) -> Option<T> {
+        let value = *self.by_hash.lock().await.get(&hash)?;
+        self.touched.lock().await.insert(value);
+        Some(value)
+    }
+
+    /// Records a freshly created entry under `hash`, marking it touched.
+    async fn insert(&self, hash: u64, value: T) {
+        self.by_hash.lock().await.insert(hash, value);
+        self.touched.lock().await.insert(value);
+    }
+
+    /// Re-points the entry stored under `hash` from `old` to `new`,
+    /// dropping `old` from the touched set and marking `new` touched.
+    async fn repoint(&self, hash: u64, old: T, new: T) {
+        self.by_hash.lock().await.insert(hash, new);
+        let mut touched = self.touched.lock().await;
+        touched.remove(&old);
+        touched.insert(new);
+    }
+
+    /// Forgets and returns every entry that wasn't touched since the last
+    /// drain and that `protect` doesn't exempt, then resets the touched set
+    /// for the next evaluation.
+    async fn drain_orphans(&self, protect: impl Fn(&T) -> bool) -> Vec<T> {
+        let orphaned: Vec<(u64, T)> = {
+            let touched = self.touched.lock().await;
+            let by_hash = self.by_hash.lock().await;
+            by_hash
+                .iter()
+                .filter(|(_, value)| !touched.contains(value) && !protect(value))
+                .map(|(hash, value)| (*hash, *value))
+                .collect()
+        };
+
+        if !orphaned.is_empty() {
+            let mut by_hash = self.by_hash.lock().await;
+            for (hash, _) in &orphaned {
+                by_hash.remove(hash);
+            }
+        }
+
+        self.touched.lock().await.clear();
+
+        orphaned.into_iter().map(|(_, value)| value).collect()
+    }
+}
+
+/// Content-addressed cache of nodes created through the Lua bindings.
+///
+/// Re-evaluating a live-coding chunk hashes each node bottom-up -- a
+/// processor's hash folds in its name and each input's `(source hash,
+/// source output)`, a constant's hash folds in its value -- so an
+/// unchanged subtree always lands on the same hash and `get`/`insert`
+/// callers can reuse the node already live on the server instead of
+/// rebuilding it. [`NodeCache::drain_orphans`] then reports whatever
+/// *stopped* being touched, so the caller can tear those down.
+#[derive(Default)]
+pub struct NodeCache {
+    cache: TouchedCache<NodeIndex>,
+    hash_of: Mutex<HashMap<NodeIndex, u64>>,
+    mix_sources: Mutex<HashMap<usize, NodeIndex>>,
+}
+
+impl NodeCache {
+    /// Hash for a constant-value leaf node, e.g. `ConstantF32`'s bits.
+    pub fn hash_constant(tag: &str, bytes: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        tag.hash(&mut hasher);
+        bytes.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Hash for a processor node, from its name and each input's
+    /// `(source hash, source output)`.
+    pub fn hash_processor(name: &str, inputs: &[(u64, NameOrIndex)]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        for (source_hash, output) in inputs {
+            source_hash.hash(&mut hasher);
+            match output {
+                NameOrIndex::Index(i) => i.hash(&mut hasher),
+                NameOrIndex::Name(n) => n.hash(&mut hasher),
+            }
+        }
+        hasher.finish()
+    }
+
+    /// The hash `index` was last inserted under. A node the cache doesn't
+    /// know about (e.g. one a previous `drain_orphans` forgot) hashes
+    /// to a value derived from its `Debug` form instead -- never collides
+    /// with a real cache entry, but is still a pure function of the node.
+    pub async fn hash_of(&self, index: NodeIndex) -> u64 {
+        if let Some(hash) = self.hash_of.lock().await.get(&index) {
+            return *hash;
+        }
+        let mut hasher = DefaultHasher::new();
+        format!("{index:?}").hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Looks `hash` up, marking it touched on a hit.
+    pub async fn get(&self, hash: u64) -> Option<NodeIndex> {
+        self.cache.get(hash).await
+    }
+
+    /// Records a freshly created node under `hash`, marking it touched.
+    pub async fn insert(&self, hash: u64, index: NodeIndex) {
+        self.cache.insert(hash, index).await;
+        self.hash_of.lock().await.insert(index, hash);
+    }
+
+    /// Records which node currently feeds `channel`, so it survives
+    /// `drain_orphans` even across an evaluation that doesn't touch it.
+    pub async fn set_mix_source(&self, channel: usize, index: NodeIndex) {
+        self.mix_sources.lock().await.insert(channel, index);
+    }
+
+    /// Re-points the hash that used to resolve to `old` at `new` instead,
+    /// marks `new` touched in `old`'s place, and carries over `old`'s
+    /// mix-source protection.
+    /// Used when `LuaNode::replace` swaps a live node out from under its
+    /// hash: without this, the old hash would keep pointing at a node the
+    /// server already tore down via `ReplaceNode` (so the next
+    /// `drain_orphans` would ask to remove it a second time), `new` would
+    /// stay invisible to future cache hits, and a replaced node still
+    /// feeding a mixer channel would lose the protection that's supposed to
+    /// keep `drain_orphans` from tearing it down.
+    pub async fn replace(&self, old: NodeIndex, new: NodeIndex) {
+        let mut hash_of = self.hash_of.lock().await;
+        let Some(hash) = hash_of.remove(&old) else {
+            return;
+        };
+        hash_of.insert(new, hash);
+        drop(hash_of);
+
+        self.cache.repoint(hash, old, new).await;
+
+        for index in self.mix_sources.lock().await.values_mut() {
+            if *index == old {
+                *index = new;
+            }
+        }
+    }
+
+    /// Forgets and returns every cached node that wasn't touched by the
+    /// evaluation just finished and isn't feeding a mixer channel, then
+    /// resets the touched set for the next evaluation. The caller is
+    /// responsible for actually removing the returned nodes server-side.
+    pub async fn drain_orphans(&self) -> Vec<NodeIndex> {
+        let mix_sources = self.mix_sources.lock().await;
+        let orphaned = self
+            .cache
+            .drain_orphans(|index| mix_sources.values().any(|fed| fed == index))
+            .await;
+        drop(mix_sources);
+
+        if !orphaned.is_empty() {
+            let mut hash_of = self.hash_of.lock().await;
+            for index in &orphaned {
+                hash_of.remove(index);
+            }
+        }
+
+        orphaned
+    }
+}
+
+/// Content-addressed cache of live `Subscribe`s, sharing [`NodeCache`]'s
+/// touched/orphan discipline via [`TouchedCache`] but keyed by `(node,
+/// output, kind)` instead of a node's structural hash.
+///
+/// Re-running a live-coding chunk that calls `:meter()` on a node whose
+/// subscription is already open reuses the existing subscription id instead
+/// of asking the server to open another one every evaluation; a
+/// subscription that stops being requested is reported by `drain_orphans`
+/// so the caller can send `GraphOp::Unsubscribe` for it, the same way
+/// `NodeCache::drain_orphans` reports nodes to `RemoveNode`.
+#[derive(Default)]
+pub struct SubscriptionCache {
+    cache: TouchedCache<u32>,
+}
+
+impl SubscriptionCache {
+    /// Hash for a `(node, output, kind)` triple, the subscription's
+    /// identity for caching purposes.
+    pub fn hash(node: NodeIndex, output: &NameOrIndex, kind: &MeterKind) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        node.hash(&mut hasher);
+        match output {
+            NameOrIndex::Index(i) => i.hash(&mut hasher),
+            NameOrIndex::Name(n) => n.hash(&mut hasher),
+        }
+        match kind {
+            MeterKind::Peak => 0u8.hash(&mut hasher),
+            MeterKind::Rms => 1u8.hash(&mut hasher),
+            MeterKind::RawBlock => 2u8.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    /// Looks `hash` up, marking it touched on a hit.
+    pub async fn get(&self, hash: u64) -> Option<u32> {
+        self.cache.get(hash).await
+    }
+
+    /// Records a freshly opened subscription under `hash`, marking it
+    /// touched.
+    pub async fn insert(&self, hash: u64, subscription_id: u32) {
+        self.cache.insert(hash, subscription_id).await;
+    }
+
+    /// Forgets and returns every subscription that wasn't touched by the
+    /// evaluation just finished, then resets the touched set for the next
+    /// evaluation. The caller is responsible for actually unsubscribing
+    /// server-side.
+    pub async fn drain_orphans(&self) -> Vec<u32> {
+        self.cache.drain_orphans(|_| false).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_processor_is_order_sensitive_and_deterministic() {
+        let a = NodeCache::hash_processor(
+            "SineOscillator",
+            &[(1, NameOrIndex::Index(0)), (2, NameOrIndex::Index(1))],
+        );
+        let b = NodeCache::hash_processor(
+            "SineOscillator",
+            &[(1, NameOrIndex::Index(0)), (2, NameOrIndex::Index(1))],
+        );
+        let swapped = NodeCache::hash_processor(
+            "SineOscillator",
+            &[(2, NameOrIndex::Index(1)), (1, NameOrIndex::Index(0))],
+        );
+        assert_eq!(a, b);
+        assert_ne!(a, swapped);
+    }
+
+    #[test]
+    fn hash_constant_distinguishes_tag_and_bytes() {
+        let a = NodeCache::hash_constant("f32", &1.0f32.to_le_bytes());
+        let b = NodeCache::hash_constant("f32", &2.0f32.to_le_bytes());
+        let c = NodeCache::hash_constant("bool", &1.0f32.to_le_bytes());
+        assert_ne!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn subscription_hash_distinguishes_node_output_and_kind() {
+        let a =
+            SubscriptionCache::hash(NodeIndex::new(1), &NameOrIndex::Index(0), &MeterKind::Peak);
+        let b =
+            SubscriptionCache::hash(NodeIndex::new(2), &NameOrIndex::Index(0), &MeterKind::Peak);
+        let c =
+            SubscriptionCache::hash(NodeIndex::new(1), &NameOrIndex::Index(1), &MeterKind::Peak);
+        let d = SubscriptionCache::hash(NodeIndex::new(1), &NameOrIndex::Index(0), &MeterKind::Rms);
+        assert_ne!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(a, d);
+    }
+
+    #[tokio::test]
+    async fn get_marks_touched_and_drain_orphans_resets_it() {
+        let cache = NodeCache::default();
+        assert_eq!(cache.get(42).await, None);
+
+        let index = NodeIndex::new(1);
+        cache.insert(42, index).await;
+        assert_eq!(cache.get(42).await, Some(index));
+
+        // Touched by `insert` and the `get` above, so it survives this
+        // evaluation's drain...
+        assert_eq!(cache.drain_orphans().await, Vec::new());
+        // ...but `drain_orphans` also resets the touched set, so without
+        // another lookup the node is orphaned on the next evaluation.
+        assert_eq!(cache.drain_orphans().await, vec![index]);
+    }
+
+    #[tokio::test]
+    async fn drain_orphans_spares_mix_fed_nodes_even_when_untouched() {
+        let cache = NodeCache::default();
+        let mix_fed = NodeIndex::new(1);
+        let orphan = NodeIndex::new(2);
+
+        cache.insert(1, mix_fed).await;
+        cache.insert(2, orphan).await;
+        cache.set_mix_source(0, mix_fed).await;
+
+        // End the evaluation both nodes were created in.
+        assert_eq!(cache.drain_orphans().await, Vec::new());
+
+        // A later evaluation that touches neither node should still spare
+        // `mix_fed`, since it's feeding a mixer channel.
+        let orphans = cache.drain_orphans().await;
+        assert_eq!(orphans, vec![orphan]);
+
+        // Orphans are forgotten, so draining again finds nothing new.
+        assert_eq!(cache.drain_orphans().await, Vec::new());
+    }
+
+    #[tokio::test]
+    async fn replace_carries_over_hash_and_mix_source_protection() {
+        let cache = NodeCache::default();
+        let old = NodeIndex::new(1);
+        let new = NodeIndex::new(2);
+
+        cache.insert(7, old).await;
+        cache.set_mix_source(0, old).await;
+        cache.replace(old, new).await;
+
+        // End the evaluation the replace happened in.
+        assert_eq!(cache.drain_orphans().await, Vec::new());
+
+        // A later evaluation that never looks `new` up again should still
+        // spare it: mix-source protection must have followed the replace
+        // from `old` to `new`, not stayed pointed at the torn-down node.
+        assert_eq!(cache.drain_orphans().await, Vec::new());
+
+        // And the hash still resolves to `new`, not the torn-down `old`.
+        assert_eq!(cache.get(7).await, Some(new));
+    }
+
+    #[tokio::test]
+    async fn subscription_cache_reuses_hits_and_drains_untouched_ids() {
+        let cache = SubscriptionCache::default();
+        assert_eq!(cache.get(9).await, None);
+
+        cache.insert(9, 100).await;
+        assert_eq!(cache.get(9).await, Some(100));
+
+        // Touched by `insert` and the `get` above.
+        assert_eq!(cache.drain_orphans().await, Vec::new());
+        // Untouched by the next evaluation, so it's reported as an orphan.
+        assert_eq!(cache.drain_orphans().await, vec![100]);
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     sync::{Arc, Weak},
     time::Duration,
@@ -6,35 +7,107 @@ use std::{
 
 use anyhow::Result;
 use convert_case::{Case, Casing};
+use log::{error, info};
 use mlua::*;
 use raug_graph::graph::NodeIndex;
-use raug_server::graph::{GraphOp, GraphOpResponse, NameOrIndex};
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use raug_server::graph::{GraphOp, GraphOpResponse, MeterKind, NameOrIndex};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::Mutex,
+};
+
+use crate::{
+    cache::{NodeCache, SubscriptionCache},
+    graph::{value_to_output, LuaMeter, LuaMixer, LuaNode, LuaSignal},
+    transport::{ReliabilityOptions, ReliableTransport},
+};
+
+/// `NodeIndex` values at or above this are never issued by the server;
+/// they're stand-ins for "the node this op will create", minted by
+/// [`Client::request`] from `next_placeholder` and resolved server-side
+/// once their batch is flushed.
+///
+/// They're also resolved *client*-side: once a batch flushes, `request`
+/// rewrites any of these that show up in a later op's `NodeIndex` fields
+/// against `placeholder_map`, so a placeholder that escaped into a
+/// `LuaNode`/`LuaOutput` (or the node cache) while its batch was open keeps
+/// working once the batch is gone. Minting from a single client-wide
+/// counter (rather than each batch's own op-index) means two separate
+/// `transaction`s can never hand out the same placeholder value.
+const BATCH_PLACEHOLDER_BASE: usize = u32::MAX as usize / 2;
 
-use crate::graph::{LuaMixer, LuaNode, value_to_output};
+/// Upper bound on a single live-coding chunk's length, as read off the
+/// length-prefix `serve_connection` frames its input with. Lua source
+/// doesn't need more than this, and without a cap a connected peer could
+/// claim a length near `u32::MAX` and make us allocate gigabytes before
+/// ever reading a byte of content.
+const MAX_CHUNK_LEN: usize = 1024 * 1024;
 
 pub struct Client {
     pub sref: Weak<Self>,
     pub lua: Lua,
-    pub socket: Arc<UdpSocket>,
     pub remote_addr: SocketAddr,
+    transport: Arc<ReliableTransport>,
+    /// Ops queued by an open `transaction { ... }` block, paired with the
+    /// placeholder `request` minted for each (if any), awaiting a single
+    /// flush. `None` means requests are sent (and awaited) immediately.
+    batch: Mutex<Option<Vec<(GraphOp, Option<NodeIndex>)>>>,
+    /// Source of placeholder `NodeIndex` values handed out by `request` for
+    /// ops enqueued in an open batch. Shared across every batch a `Client`
+    /// ever opens, so placeholders from different `transaction`s never
+    /// collide.
+    next_placeholder: Mutex<usize>,
+    /// Maps a batch placeholder `NodeIndex` to the real one the server
+    /// assigned once its batch flushed. Consulted by `request` so a
+    /// placeholder that leaked into a later op (via a `LuaNode`/`LuaOutput`
+    /// or the node cache) is rewritten before it ever reaches the wire.
+    placeholder_map: Mutex<HashMap<NodeIndex, NodeIndex>>,
+    /// Content-addressed cache enabling incremental hot-reload: re-running
+    /// a chunk reuses any node whose inputs haven't changed instead of
+    /// rebuilding it.
+    pub(crate) node_cache: NodeCache,
+    /// Content-addressed cache of open `Subscribe`s, so re-running a chunk
+    /// reuses an unchanged node's meter instead of opening another one.
+    subscription_cache: SubscriptionCache,
+    /// Live meters, keyed by subscription id, so a cache hit in
+    /// `subscription_cache` can hand back the same `LuaMeter` instance.
+    meters: Mutex<HashMap<u32, LuaMeter>>,
 }
 
 impl Client {
     pub async fn bind(
         local_addr: impl ToSocketAddrs,
         remote_addr: SocketAddr,
+    ) -> Result<Arc<Self>> {
+        Self::bind_with_options(local_addr, remote_addr, ReliabilityOptions::default()).await
+    }
+
+    /// Like [`Client::bind`], but lets the caller tune the UDP reliability
+    /// layer's retransmission timeout, retry budget, and whether ops are
+    /// sent strictly in order (see [`ReliabilityOptions`]).
+    pub async fn bind_with_options(
+        local_addr: impl ToSocketAddrs,
+        remote_addr: SocketAddr,
+        options: ReliabilityOptions,
     ) -> Result<Arc<Self>> {
         let socket = Arc::new(UdpSocket::bind(local_addr).await?);
         socket.connect(remote_addr).await?;
+        let transport = ReliableTransport::spawn(socket, remote_addr, options);
 
         let lua = Lua::new();
 
         let this = Arc::new_cyclic(|sref| Self {
             sref: sref.clone(),
-            socket,
+            transport,
             lua,
             remote_addr,
+            batch: Mutex::new(None),
+            next_placeholder: Mutex::new(BATCH_PLACEHOLDER_BASE),
+            placeholder_map: Mutex::new(HashMap::new()),
+            node_cache: NodeCache::default(),
+            subscription_cache: SubscriptionCache::default(),
+            meters: Mutex::new(HashMap::new()),
         });
 
         this.lua.globals().set(
@@ -82,6 +155,42 @@ impl Client {
             })?,
         )?;
 
+        this.lua.globals().set(
+            "transaction",
+            this.lua.create_async_function({
+                let client = this.clone();
+                move |_lua, body: Function| {
+                    let client = client.clone();
+                    async move {
+                        client.begin_batch().await;
+                        let result = body.call_async::<Value>(()).await;
+                        client.flush_batch().await?;
+                        Ok(result?)
+                    }
+                }
+            })?,
+        )?;
+
+        this.lua.globals().set(
+            "vec",
+            this.lua.create_async_function({
+                let client = this.clone();
+                move |_lua, args: MultiValue| {
+                    let client = client.clone();
+                    async move {
+                        let mut channels = Vec::with_capacity(args.len());
+                        for arg in args.iter() {
+                            channels.push(value_to_output(client.clone(), arg.clone()).await?);
+                        }
+                        Ok(LuaSignal {
+                            client: client.sref.clone(),
+                            channels,
+                        })
+                    }
+                }
+            })?,
+        )?;
+
         this.register_lua_procs([
             "PhaseAccumulator",
             "SineOscillator",
@@ -95,8 +204,166 @@ impl Client {
         Ok(this)
     }
 
+    /// Sends `op` to the server, or, if a `transaction` is currently open,
+    /// enqueues it and returns immediately with a placeholder response so
+    /// the caller can keep chaining ops without a round-trip per call.
     pub async fn request(&self, op: GraphOp) -> Result<GraphOpResponse> {
-        op.request(&self.socket, self.remote_addr).await
+        let op = self.resolve_placeholders(op).await;
+
+        let mut batch = self.batch.lock().await;
+        if let Some(ops) = batch.as_mut() {
+            let produces_index = matches!(
+                op,
+                GraphOp::AddProcessor { .. }
+                    | GraphOp::AddConstantF32(_)
+                    | GraphOp::AddConstantBool(_)
+                    | GraphOp::AddConstantString(_)
+                    | GraphOp::ReplaceNode { .. }
+            );
+            let placeholder = if produces_index {
+                let mut next_placeholder = self.next_placeholder.lock().await;
+                let placeholder = NodeIndex::new(*next_placeholder);
+                *next_placeholder += 1;
+                Some(placeholder)
+            } else {
+                None
+            };
+            ops.push((op, placeholder));
+            return Ok(match placeholder {
+                Some(placeholder) => GraphOpResponse::NodeIndex(placeholder),
+                None => GraphOpResponse::None,
+            });
+        }
+        drop(batch);
+        self.transport.send(&op).await
+    }
+
+    /// Rewrites any `NodeIndex` field of `op` that's a still-outstanding
+    /// batch placeholder into the real index `placeholder_map` resolved it
+    /// to. A placeholder minted by the batch currently being built (i.e.
+    /// not in the map yet) is left alone -- the server resolves those
+    /// within the `GraphOp::Batch` itself.
+    async fn resolve_placeholders(&self, op: GraphOp) -> GraphOp {
+        let map = self.placeholder_map.lock().await;
+        Self::resolve_op(op, &map)
+    }
+
+    fn resolve_op(op: GraphOp, map: &HashMap<NodeIndex, NodeIndex>) -> GraphOp {
+        let resolve = |index: NodeIndex| map.get(&index).copied().unwrap_or(index);
+        match op {
+            GraphOp::Connect {
+                source,
+                source_output,
+                target,
+                target_input,
+            } => GraphOp::Connect {
+                source: resolve(source),
+                source_output,
+                target: resolve(target),
+                target_input,
+            },
+            GraphOp::ReplaceNode {
+                replaced,
+                replacement,
+            } => GraphOp::ReplaceNode {
+                replaced: resolve(replaced),
+                replacement: resolve(replacement),
+            },
+            GraphOp::RemoveNode(index) => GraphOp::RemoveNode(resolve(index)),
+            GraphOp::AddToMix {
+                mixer_channel,
+                source,
+                source_output,
+            } => GraphOp::AddToMix {
+                mixer_channel,
+                source: resolve(source),
+                source_output,
+            },
+            GraphOp::Subscribe { node, output, kind } => GraphOp::Subscribe {
+                node: resolve(node),
+                output,
+                kind,
+            },
+            GraphOp::Batch(ops) => GraphOp::Batch(
+                ops.into_iter()
+                    .map(|op| Self::resolve_op(op, map))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+
+    /// Opens a batch: subsequent `request` calls enqueue ops instead of
+    /// sending them. Nesting isn't supported; a nested `transaction` keeps
+    /// appending to the same batch, and its exit flushes that batch, so ops
+    /// the outer block issues after it are sent unbatched.
+    async fn begin_batch(&self) {
+        self.batch.lock().await.get_or_insert_with(Vec::new);
+    }
+
+    /// Flushes any ops queued since `begin_batch` as a single
+    /// `GraphOp::Batch`, letting the server resolve the placeholder indices
+    /// handed out by `request` against each op's real result, then records
+    /// those resolutions in `placeholder_map` so any placeholder already
+    /// handed to Lua (baked into a `LuaNode`/`LuaOutput` or the node cache)
+    /// keeps working transparently from here on.
+    async fn flush_batch(&self) -> Result<Vec<NodeIndex>> {
+        let entries = self.batch.lock().await.take().unwrap_or_default();
+        if entries.is_empty() {
+            return Ok(Vec::new());
+        }
+        let (ops, placeholders): (Vec<GraphOp>, Vec<Option<NodeIndex>>) =
+            entries.into_iter().unzip();
+        let resp = self.request(GraphOp::Batch(ops)).await?;
+        let results = resp
+            .as_batch()
+            .ok_or_else(|| anyhow::anyhow!("expected a GraphOpResponse::Batch"))?;
+
+        let mut placeholder_map = self.placeholder_map.lock().await;
+        let mut resolved = Vec::with_capacity(results.len());
+        for (placeholder, result) in placeholders.iter().zip(results) {
+            if let (Some(placeholder), Some(&index)) = (placeholder, result.as_node_index()) {
+                placeholder_map.insert(*placeholder, index);
+                resolved.push(index);
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Subscribes to a stream of `kind` measurements for `node`'s `output`,
+    /// returning a [`LuaMeter`] that always reflects the latest one.
+    /// Re-running a chunk that calls `:meter()` on the same
+    /// `(node, output, kind)` reuses the existing subscription instead of
+    /// opening another one server-side.
+    pub async fn subscribe(
+        &self,
+        node: NodeIndex,
+        output: NameOrIndex,
+        kind: MeterKind,
+    ) -> Result<LuaMeter> {
+        let hash = SubscriptionCache::hash(node, &output, &kind);
+        if let Some(subscription_id) = self.subscription_cache.get(hash).await {
+            if let Some(meter) = self.meters.lock().await.get(&subscription_id) {
+                return Ok(meter.clone());
+            }
+        }
+
+        let resp = self
+            .request(GraphOp::Subscribe { node, output, kind })
+            .await?;
+        let subscription_id = *resp
+            .as_subscription_id()
+            .ok_or_else(|| anyhow::anyhow!("expected a GraphOpResponse::SubscriptionId"))?;
+        let receiver = self.transport.register_subscription(subscription_id).await;
+        let meter = LuaMeter::new(receiver);
+
+        self.subscription_cache.insert(hash, subscription_id).await;
+        self.meters
+            .lock()
+            .await
+            .insert(subscription_id, meter.clone());
+
+        Ok(meter)
     }
 
     fn register_lua_procs<'a>(&self, procs: impl IntoIterator<Item = &'a str>) -> Result<()> {
@@ -106,18 +373,16 @@ impl Client {
         Ok(())
     }
 
-    async fn connect_inputs_and_outputs(
+    /// Connects each of `inputs` to consecutive input slots on `node`.
+    async fn connect_inputs(
         &self,
         node: NodeIndex,
-        args: MultiValue,
-    ) -> Result<LuaNode> {
-        for (target_input, arg) in args.iter().enumerate() {
-            let (source, source_output) =
-                value_to_output(self.sref.upgrade().unwrap(), arg.clone()).await?;
-
+        inputs: &[(NodeIndex, NameOrIndex)],
+    ) -> Result<()> {
+        for (target_input, (source, source_output)) in inputs.iter().enumerate() {
             let op = GraphOp::Connect {
-                source,
-                source_output,
+                source: *source,
+                source_output: source_output.clone(),
                 target: node,
                 target_input: NameOrIndex::Index(target_input as u32),
             };
@@ -126,10 +391,7 @@ impl Client {
             assert_eq!(resp, GraphOpResponse::None);
         }
 
-        Ok(LuaNode {
-            client: self.sref.clone(),
-            index: node,
-        })
+        Ok(())
     }
 
     fn register_lua_proc(&self, proc: &str) -> Result<()> {
@@ -143,15 +405,37 @@ impl Client {
                     let proc = proc.clone();
                     let client = client.clone();
                     async move {
-                        let op = GraphOp::AddProcessor {
-                            name: proc.to_case(Case::UpperCamel),
-                        };
-                        let resp = client.request(op).await?;
-                        let target = *resp.as_node_index().unwrap();
+                        let proc_name = proc.to_case(Case::UpperCamel);
+
+                        // Resolve inputs first so we can hash bottom-up: an
+                        // unchanged subtree always yields the same hash,
+                        // which is what lets hot-reload skip rebuilding it.
+                        let mut inputs = Vec::with_capacity(args.len());
+                        let mut hash_inputs = Vec::with_capacity(args.len());
+                        for arg in args.iter() {
+                            let input = value_to_output(client.clone(), arg.clone()).await?;
+                            let source_hash = client.node_cache.hash_of(input.0).await;
+                            hash_inputs.push((source_hash, input.1.clone()));
+                            inputs.push(input);
+                        }
+                        let hash = NodeCache::hash_processor(&proc_name, &hash_inputs);
 
-                        let res = client.connect_inputs_and_outputs(target, args).await?;
+                        let target = if let Some(cached) = client.node_cache.get(hash).await {
+                            cached
+                        } else {
+                            let resp = client
+                                .request(GraphOp::AddProcessor { name: proc_name })
+                                .await?;
+                            let target = *resp.as_node_index().unwrap();
+                            client.connect_inputs(target, &inputs).await?;
+                            client.node_cache.insert(hash, target).await;
+                            target
+                        };
 
-                        Ok(res)
+                        Ok(LuaNode {
+                            client: client.sref.clone(),
+                            index: target,
+                        })
                     }
                 }
             })?,
@@ -164,8 +448,159 @@ impl Client {
         Ok(())
     }
 
+    /// Evaluates `chunk` against the persistent `Lua` state, then reaps any
+    /// previously-live node that this run didn't touch and isn't feeding a
+    /// mixer channel -- see [`NodeCache::drain_orphans`] -- and any
+    /// subscription this run didn't re-request -- see
+    /// [`SubscriptionCache::drain_orphans`].
     pub async fn eval<R: FromLuaMulti>(&self, chunk: impl AsChunk<'_>) -> Result<R> {
         let res = self.lua.load(chunk).eval_async().await?;
-        Ok(R::from_lua_multi(res, &self.lua)?)
+        let result = R::from_lua_multi(res, &self.lua)?;
+
+        for orphan in self.node_cache.drain_orphans().await {
+            self.request(GraphOp::RemoveNode(orphan)).await?;
+        }
+
+        for subscription_id in self.subscription_cache.drain_orphans().await {
+            self.meters.lock().await.remove(&subscription_id);
+            self.transport
+                .unregister_subscription(subscription_id)
+                .await;
+            self.request(GraphOp::Unsubscribe(subscription_id)).await?;
+        }
+
+        Ok(result)
+    }
+
+    /// Runs as a live-coding server: accepts TCP connections on
+    /// `listen_addr` and spawns a task per connection that reads
+    /// length-prefixed UTF-8 Lua chunks, evaluates each against this same
+    /// `Lua` state (and the same live audio graph), and writes back a
+    /// length-prefixed response frame. Never returns under normal operation.
+    pub async fn serve(self: Arc<Self>, listen_addr: impl ToSocketAddrs) -> Result<()> {
+        let listener = TcpListener::bind(listen_addr).await?;
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            info!("live-coding connection from {peer_addr}");
+            let client = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = client.serve_connection(stream).await {
+                    error!("live-coding connection from {peer_addr} failed: {err}");
+                }
+            });
+        }
+    }
+
+    async fn serve_connection(&self, mut stream: TcpStream) -> Result<()> {
+        loop {
+            let mut len_buf = [0u8; 4];
+            if stream.read_exact(&mut len_buf).await.is_err() {
+                // peer closed the connection
+                return Ok(());
+            }
+            let len = u32::from_be_bytes(len_buf) as usize;
+            if len > MAX_CHUNK_LEN {
+                error!("live-coding chunk of {len} bytes exceeds the {MAX_CHUNK_LEN}-byte limit; closing connection");
+                return Ok(());
+            }
+            let mut source = vec![0u8; len];
+            stream.read_exact(&mut source).await?;
+            let source = String::from_utf8(source)?;
+
+            let frame = match self.eval::<MultiValue>(source).await {
+                Ok(values) => {
+                    let rendered: Vec<_> = values.iter().map(|v| format!("{v:#?}")).collect();
+                    format!("ok\n{}", rendered.join("\n"))
+                }
+                Err(err) => format!("err\n{err}"),
+            };
+
+            let bytes = frame.into_bytes();
+            stream
+                .write_all(&(bytes.len() as u32).to_be_bytes())
+                .await?;
+            stream.write_all(&bytes).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_op_rewrites_a_mapped_placeholder() {
+        let real = NodeIndex::new(7);
+        let placeholder = NodeIndex::new(BATCH_PLACEHOLDER_BASE);
+        let mut map = HashMap::new();
+        map.insert(placeholder, real);
+
+        let op = GraphOp::Connect {
+            source: placeholder,
+            source_output: NameOrIndex::Index(0),
+            target: NodeIndex::new(1),
+            target_input: NameOrIndex::Index(0),
+        };
+        match Client::resolve_op(op, &map) {
+            GraphOp::Connect { source, target, .. } => {
+                assert_eq!(source, real);
+                assert_eq!(target, NodeIndex::new(1));
+            }
+            other => panic!("expected a Connect op, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn resolve_op_leaves_unmapped_placeholders_alone() {
+        let map = HashMap::new();
+        let still_open = NodeIndex::new(BATCH_PLACEHOLDER_BASE + 1);
+        match Client::resolve_op(GraphOp::RemoveNode(still_open), &map) {
+            GraphOp::RemoveNode(index) => assert_eq!(index, still_open),
+            other => panic!("expected a RemoveNode op, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn resolve_op_recurses_into_nested_batches() {
+        let real = NodeIndex::new(3);
+        let placeholder = NodeIndex::new(BATCH_PLACEHOLDER_BASE);
+        let mut map = HashMap::new();
+        map.insert(placeholder, real);
+
+        let op = GraphOp::Batch(vec![GraphOp::RemoveNode(placeholder)]);
+        let mut ops = match Client::resolve_op(op, &map) {
+            GraphOp::Batch(ops) => ops,
+            other => panic!("expected a Batch op, got {other:?}"),
+        };
+        match ops.remove(0) {
+            GraphOp::RemoveNode(index) => assert_eq!(index, real),
+            other => panic!("expected a RemoveNode op, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn two_batches_never_mint_the_same_placeholder() {
+        // Regression test: placeholders used to be minted from each batch's
+        // own op index (`BATCH_PLACEHOLDER_BASE + ops.len()`), so a second
+        // `transaction` would mint the exact same placeholder values as the
+        // first and corrupt any handle that had escaped it. They must come
+        // from a single counter shared across the whole client instead.
+        let client = Client::bind("127.0.0.1:0", "127.0.0.1:9".parse().unwrap())
+            .await
+            .unwrap();
+        let mut minted = Vec::new();
+        for _ in 0..2 {
+            client.begin_batch().await;
+            for _ in 0..3 {
+                let resp = client.request(GraphOp::AddConstantF32(0.0)).await.unwrap();
+                minted.push(*resp.as_node_index().unwrap());
+            }
+            // Discard the batch unflushed; only the minted placeholders matter.
+            let _ = client.batch.lock().await.take();
+        }
+        let (first_batch, second_batch) = minted.split_at(3);
+        for placeholder in second_batch {
+            assert!(!first_batch.contains(placeholder));
+        }
     }
 }
@@ -3,9 +3,34 @@ use std::sync::{Arc, Weak};
 use anyhow::Result;
 use mlua::{FromLua, MultiValue, UserData, Value};
 use raug_graph::graph::NodeIndex;
-use raug_server::graph::{GraphOp, NameOrIndex};
+use raug_server::graph::{GraphOp, Measurement, MeterKind, NameOrIndex};
+use tokio::sync::{watch, Mutex};
 
-use crate::client::Client;
+use crate::{cache::NodeCache, client::Client};
+
+/// Parses the optional `kind` argument `:meter(kind)` takes, defaulting to
+/// `"peak"` when omitted.
+fn parse_meter_kind(kind: Option<String>) -> Result<MeterKind> {
+    match kind.as_deref() {
+        Some("peak") | None => Ok(MeterKind::Peak),
+        Some("rms") => Ok(MeterKind::Rms),
+        Some("raw") | Some("raw_block") => Ok(MeterKind::RawBlock),
+        Some(other) => Err(mlua::Error::runtime(format!("unknown meter kind: {other}")).into()),
+    }
+}
+
+/// Looks `hash` up in `client`'s node cache, reusing the live node on a
+/// hit; on a miss, sends `op` (expected to produce a `NodeIndex`) and
+/// caches the result under `hash`.
+async fn get_or_create(client: &Arc<Client>, hash: u64, op: GraphOp) -> Result<NodeIndex> {
+    if let Some(node) = client.node_cache.get(hash).await {
+        return Ok(node);
+    }
+    let resp = client.request(op).await?;
+    let node = *resp.as_node_index().unwrap();
+    client.node_cache.insert(hash, node).await;
+    Ok(node)
+}
 
 pub async fn binary_op(
     op: &str,
@@ -15,27 +40,43 @@ pub async fn binary_op(
     rhs: NodeIndex,
     rhs_output: NameOrIndex,
 ) -> Result<LuaNode> {
-    let resp = client
-        .request(GraphOp::AddProcessor {
-            name: op.to_string(),
-        })
-        .await?;
+    let lhs_hash = client.node_cache.hash_of(lhs).await;
+    let rhs_hash = client.node_cache.hash_of(rhs).await;
+    let hash = NodeCache::hash_processor(
+        op,
+        &[
+            (lhs_hash, lhs_output.clone()),
+            (rhs_hash, rhs_output.clone()),
+        ],
+    );
 
-    let target = *resp.as_node_index().unwrap();
+    let target = if let Some(cached) = client.node_cache.get(hash).await {
+        cached
+    } else {
+        let resp = client
+            .request(GraphOp::AddProcessor {
+                name: op.to_string(),
+            })
+            .await?;
+        let target = *resp.as_node_index().unwrap();
 
-    let op0 = GraphOp::Connect {
-        source: lhs,
-        source_output: lhs_output,
-        target,
-        target_input: NameOrIndex::Index(0),
-    };
-    let op1 = GraphOp::Connect {
-        source: rhs,
-        source_output: rhs_output,
-        target,
-        target_input: NameOrIndex::Index(1),
+        let op0 = GraphOp::Connect {
+            source: lhs,
+            source_output: lhs_output,
+            target,
+            target_input: NameOrIndex::Index(0),
+        };
+        let op1 = GraphOp::Connect {
+            source: rhs,
+            source_output: rhs_output,
+            target,
+            target_input: NameOrIndex::Index(1),
+        };
+        tokio::try_join!(client.request(op0), client.request(op1))?;
+
+        client.node_cache.insert(hash, target).await;
+        target
     };
-    tokio::try_join!(client.request(op0), client.request(op1))?;
 
     Ok(LuaNode {
         client: Arc::downgrade(&client),
@@ -49,22 +90,31 @@ pub async fn unary_op(
     node: NodeIndex,
     node_output: NameOrIndex,
 ) -> Result<LuaNode> {
-    let resp = client
-        .request(GraphOp::AddProcessor {
-            name: op.to_string(),
-        })
-        .await?;
+    let source_hash = client.node_cache.hash_of(node).await;
+    let hash = NodeCache::hash_processor(op, &[(source_hash, node_output.clone())]);
 
-    let target = *resp.as_node_index().unwrap();
+    let target = if let Some(cached) = client.node_cache.get(hash).await {
+        cached
+    } else {
+        let resp = client
+            .request(GraphOp::AddProcessor {
+                name: op.to_string(),
+            })
+            .await?;
+        let target = *resp.as_node_index().unwrap();
 
-    client
-        .request(GraphOp::Connect {
-            source: node,
-            source_output: node_output,
-            target,
-            target_input: NameOrIndex::Index(0),
-        })
-        .await?;
+        client
+            .request(GraphOp::Connect {
+                source: node,
+                source_output: node_output,
+                target,
+                target_input: NameOrIndex::Index(0),
+            })
+            .await?;
+
+        client.node_cache.insert(hash, target).await;
+        target
+    };
 
     Ok(LuaNode {
         client: Arc::downgrade(&client),
@@ -79,26 +129,26 @@ pub async fn value_to_output(
     match value {
         Value::Integer(value) => {
             let value = value as f32;
-            let node = client.request(GraphOp::AddConstantF32(value)).await?;
-            let node = *node.as_node_index().unwrap();
+            let hash = NodeCache::hash_constant("ConstantF32", &value.to_bits().to_be_bytes());
+            let node = get_or_create(&client, hash, GraphOp::AddConstantF32(value)).await?;
             Ok((node, NameOrIndex::Index(0)))
         }
         Value::Number(value) => {
             let value = value as f32;
-            let node = client.request(GraphOp::AddConstantF32(value)).await?;
-            let node = *node.as_node_index().unwrap();
+            let hash = NodeCache::hash_constant("ConstantF32", &value.to_bits().to_be_bytes());
+            let node = get_or_create(&client, hash, GraphOp::AddConstantF32(value)).await?;
             Ok((node, NameOrIndex::Index(0)))
         }
         Value::Boolean(value) => {
-            let node = client.request(GraphOp::AddConstantBool(value)).await?;
-            let node = *node.as_node_index().unwrap();
+            let hash = NodeCache::hash_constant("ConstantBool", &[value as u8]);
+            let node = get_or_create(&client, hash, GraphOp::AddConstantBool(value)).await?;
             Ok((node, NameOrIndex::Index(0)))
         }
         Value::String(value) => {
-            let node = client
-                .request(GraphOp::AddConstantString(value.to_string_lossy()))
-                .await?;
-            let node = *node.as_node_index().unwrap();
+            let value = value.to_string_lossy();
+            let hash = NodeCache::hash_constant("ConstantString", value.as_bytes());
+            let node =
+                get_or_create(&client, hash, GraphOp::AddConstantString(value.clone())).await?;
             Ok((node, NameOrIndex::Index(0)))
         }
         Value::UserData(value) => {
@@ -127,14 +177,16 @@ impl UserData for LuaNode {
             move |_lua, mut this, replacement: Value| async move {
                 let client = this.client.upgrade().unwrap();
                 let (replacement, _) = value_to_output(client.clone(), replacement).await?;
+                let old_index = this.index;
                 let node = client
                     .request(GraphOp::ReplaceNode {
-                        replaced: this.index,
+                        replaced: old_index,
                         replacement,
                     })
                     .await?;
                 let node = *node.as_node_index().unwrap();
                 this.index = node;
+                client.node_cache.replace(old_index, node).await;
                 Ok(LuaNode {
                     client: Arc::downgrade(&client),
                     index: node,
@@ -142,6 +194,24 @@ impl UserData for LuaNode {
             },
         );
 
+        methods.add_method("channels", move |_lua, this, count: u32| {
+            Ok(LuaSignal {
+                client: this.client.clone(),
+                channels: vec![(this.index, NameOrIndex::Index(0)); count as usize],
+            })
+        });
+
+        methods.add_async_method(
+            "meter",
+            move |_lua, this, kind: Option<String>| async move {
+                let client = this.client.upgrade().unwrap();
+                let kind = parse_meter_kind(kind)?;
+                Ok(client
+                    .subscribe(this.index, NameOrIndex::Index(0), kind)
+                    .await?)
+            },
+        );
+
         methods.add_meta_method("__index", move |_lua, this, key: Value| match key {
             Value::Integer(v) => Ok(LuaOutput {
                 client: this.client.clone(),
@@ -228,6 +298,24 @@ pub struct LuaOutput {
 
 impl UserData for LuaOutput {
     fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("channels", move |_lua, this, count: u32| {
+            Ok(LuaSignal {
+                client: this.client.clone(),
+                channels: vec![(this.node, this.output.clone()); count as usize],
+            })
+        });
+
+        methods.add_async_method(
+            "meter",
+            move |_lua, this, kind: Option<String>| async move {
+                let client = this.client.upgrade().unwrap();
+                let kind = parse_meter_kind(kind)?;
+                Ok(client
+                    .subscribe(this.node, this.output.clone(), kind)
+                    .await?)
+            },
+        );
+
         methods.add_async_meta_method("__add", move |_lua, lhs, rhs: Value| async move {
             let client = lhs.client.upgrade().unwrap();
             let (rhs, rhs_output) = value_to_output(client.clone(), rhs).await?;
@@ -281,6 +369,27 @@ impl UserData for LuaMixer {
                 let key = key.as_integer().unwrap();
                 let val = val.as_function().unwrap();
                 let val: Value = val.call_async(()).await?;
+
+                if let Value::UserData(ud) = &val {
+                    if let Ok(signal) = ud.borrow::<LuaSignal>() {
+                        for (channel, (source, source_output)) in signal.channels.iter().enumerate()
+                        {
+                            client
+                                .request(GraphOp::AddToMix {
+                                    mixer_channel: key as usize + channel,
+                                    source: *source,
+                                    source_output: source_output.clone(),
+                                })
+                                .await?;
+                            client
+                                .node_cache
+                                .set_mix_source(key as usize + channel, *source)
+                                .await;
+                        }
+                        return Ok(());
+                    }
+                }
+
                 let (index, output) = value_to_output(client.clone(), val).await?;
                 client
                     .request(GraphOp::AddToMix {
@@ -289,8 +398,128 @@ impl UserData for LuaMixer {
                         source_output: output,
                     })
                     .await?;
+                client.node_cache.set_mix_source(key as usize, index).await;
                 Ok(())
             },
         );
     }
 }
+
+/// An ordered list of `(node, output)` channels, letting a single Lua value
+/// stand for a stereo/N-channel signal instead of one mono output at a time.
+/// Construct one with `vec(left, right, ...)`, or turn a mono node/output
+/// into `n` identical channels with `:channels(n)`.
+#[derive(Clone, FromLua)]
+pub struct LuaSignal {
+    pub client: Weak<Client>,
+    pub channels: Vec<(NodeIndex, NameOrIndex)>,
+}
+
+impl LuaSignal {
+    /// Applies `op` channel-wise against `rhs`. If `rhs` is itself a
+    /// `LuaSignal` its channel count must match; otherwise `rhs` is
+    /// resolved once and broadcast over every channel, the same way
+    /// `value_to_output` treats a bare scalar.
+    async fn broadcast(&self, op: &str, rhs: Value) -> Result<LuaSignal> {
+        let client = self.client.upgrade().unwrap();
+
+        let mut matched_signal = None;
+        if let Value::UserData(ud) = &rhs {
+            if let Ok(signal) = ud.borrow::<LuaSignal>() {
+                matched_signal = Some(signal.channels.clone());
+            }
+        }
+        let rhs_channels = if let Some(channels) = matched_signal {
+            if channels.len() != self.channels.len() {
+                return Err(mlua::Error::runtime(format!(
+                    "cannot {op} a {}-channel signal with a {}-channel signal",
+                    self.channels.len(),
+                    channels.len()
+                ))
+                .into());
+            }
+            channels
+        } else {
+            let channel = value_to_output(client.clone(), rhs).await?;
+            vec![channel; self.channels.len()]
+        };
+
+        let mut channels = Vec::with_capacity(self.channels.len());
+        for ((lhs_node, lhs_output), (rhs_node, rhs_output)) in
+            self.channels.iter().zip(rhs_channels)
+        {
+            let res = binary_op(
+                op,
+                client.clone(),
+                *lhs_node,
+                lhs_output.clone(),
+                rhs_node,
+                rhs_output,
+            )
+            .await?;
+            channels.push((res.index, NameOrIndex::Index(0)));
+        }
+
+        Ok(LuaSignal {
+            client: self.client.clone(),
+            channels,
+        })
+    }
+}
+
+impl UserData for LuaSignal {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_meta_method("__add", move |_lua, lhs, rhs: Value| async move {
+            lhs.broadcast("Add", rhs).await
+        });
+        methods.add_async_meta_method("__sub", move |_lua, lhs, rhs: Value| async move {
+            lhs.broadcast("Sub", rhs).await
+        });
+        methods.add_async_meta_method("__mul", move |_lua, lhs, rhs: Value| async move {
+            lhs.broadcast("Mul", rhs).await
+        });
+        methods.add_async_meta_method("__div", move |_lua, lhs, rhs: Value| async move {
+            lhs.broadcast("Div", rhs).await
+        });
+        methods.add_async_meta_method("__unm", move |_lua, this, _: ()| async move {
+            let client = this.client.upgrade().unwrap();
+            let mut channels = Vec::with_capacity(this.channels.len());
+            for (node, output) in &this.channels {
+                let res = unary_op("Neg", client.clone(), *node, output.clone()).await?;
+                channels.push((res.index, NameOrIndex::Index(0)));
+            }
+            Ok(LuaSignal {
+                client: this.client.clone(),
+                channels,
+            })
+        });
+    }
+}
+
+/// A live handle to a `Subscribe`d measurement stream, returned by
+/// `LuaNode:meter()`/`LuaOutput:meter()`. Always reflects the latest value
+/// the server has pushed; `:read()` never blocks waiting for a *new* one.
+#[derive(Clone, FromLua)]
+pub struct LuaMeter {
+    receiver: Arc<Mutex<watch::Receiver<Measurement>>>,
+}
+
+impl LuaMeter {
+    pub(crate) fn new(receiver: watch::Receiver<Measurement>) -> Self {
+        Self {
+            receiver: Arc::new(Mutex::new(receiver)),
+        }
+    }
+}
+
+impl UserData for LuaMeter {
+    fn add_methods<M: mlua::UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_async_method("read", move |lua, this, _: ()| async move {
+            let measurement = this.receiver.lock().await.borrow().clone();
+            match measurement {
+                Measurement::Peak(v) | Measurement::Rms(v) => Ok(Value::Number(v as f64)),
+                Measurement::RawBlock(block) => Ok(Value::Table(lua.create_sequence_from(block)?)),
+            }
+        });
+    }
+}
@@ -5,8 +5,10 @@ use clap::Parser;
 
 use client::Client;
 
+pub mod cache;
 pub mod client;
 pub mod graph;
+pub mod transport;
 
 #[derive(Parser)]
 struct Args {
@@ -15,6 +17,11 @@ struct Args {
 
     #[clap(short, long, default_value = "127.0.0.1:5050")]
     remote_addr: SocketAddr,
+
+    /// If set, run as a live-coding server listening for Lua chunks on this
+    /// address instead of running the built-in one-shot demo chunk.
+    #[clap(short, long)]
+    serve_addr: Option<SocketAddr>,
 }
 
 #[tokio::main]
@@ -23,21 +30,25 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     let client = Client::bind(args.local_addr, args.remote_addr).await?;
 
-    client
-        .exec(
-            r#"
-                    mix[1] = function()
-                        return sine_oscillator(440) * 0.1
-                    end
-
-                    play()
-                    sleep(1)
-                    stop()
-
-                "#
-            .trim(),
-        )
-        .await?;
+    if let Some(serve_addr) = args.serve_addr {
+        client.serve(serve_addr).await?;
+    } else {
+        client
+            .exec(
+                r#"
+                        mix[1] = function()
+                            return sine_oscillator(440) * 0.1
+                        end
+
+                        play()
+                        sleep(1)
+                        stop()
+
+                    "#
+                .trim(),
+            )
+            .await?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,295 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use log::error;
+use raug_server::graph::{GraphOp, GraphOpResponse, Measurement};
+use tokio::{
+    net::UdpSocket,
+    sync::{oneshot, watch, Mutex},
+    time::sleep,
+};
+
+/// Discriminates the two kinds of datagram this transport demultiplexes:
+/// an acknowledgement of a specific sent op, or an unsolicited measurement
+/// frame pushed by a `Subscribe`d meter.
+const FRAME_RESPONSE: u8 = 0;
+const FRAME_STREAM: u8 = 1;
+
+/// How the retransmission layer behaves. The defaults favor correctness
+/// (strict ordering, several retries) over raw throughput.
+#[derive(Clone, Copy, Debug)]
+pub struct ReliabilityOptions {
+    /// How long to wait for a response before the first retransmission.
+    /// Doubles after each retry.
+    pub initial_rto: Duration,
+    /// Give up and return an error after this many retransmissions.
+    pub max_retries: u32,
+    /// If true, op N+1 isn't sent until op N has been acknowledged, so
+    /// dependent ops (e.g. a `Connect` that references a node an earlier
+    /// `AddProcessor` creates) can never arrive out of order.
+    pub ordered: bool,
+}
+
+impl Default for ReliabilityOptions {
+    fn default() -> Self {
+        Self {
+            initial_rto: Duration::from_millis(100),
+            max_retries: 5,
+            ordered: true,
+        }
+    }
+}
+
+struct InFlight {
+    datagram: Vec<u8>,
+    sent_at: Instant,
+    rto: Duration,
+    retries: u32,
+    reply: oneshot::Sender<GraphOpResponse>,
+}
+
+/// A reliability layer over a connected `UdpSocket`: tags each outgoing
+/// `GraphOp` with a monotonically increasing sequence number, retransmits
+/// with exponential backoff until it sees a matching response, and ignores
+/// duplicate or late responses for ops it's already resolved.
+///
+/// Wire format is a 1-byte frame tag, then a 4-byte big-endian id (the
+/// op's sequence number, or a subscription id for stream frames), then the
+/// bincode-encoded `GraphOp` (requests), `GraphOpResponse` (responses) or
+/// `Measurement` (stream frames).
+pub struct ReliableTransport {
+    socket: Arc<UdpSocket>,
+    options: ReliabilityOptions,
+    next_seq: Mutex<u32>,
+    in_flight: Arc<Mutex<HashMap<u32, InFlight>>>,
+    /// Held for the whole round trip of an op while `options.ordered` is
+    /// set, so the next op can't be sent until this one is acknowledged.
+    send_order: Mutex<()>,
+    /// One sender per live `Subscribe`, keyed by the subscription id the
+    /// server handed back in its `GraphOpResponse`. A `watch` channel gives
+    /// exactly the semantics a meter wants: only the latest measurement is
+    /// ever kept, so a slow reader can never apply backpressure upstream.
+    subscriptions: Arc<Mutex<HashMap<u32, watch::Sender<Measurement>>>>,
+}
+
+impl ReliableTransport {
+    pub fn spawn(
+        socket: Arc<UdpSocket>,
+        _remote_addr: SocketAddr,
+        options: ReliabilityOptions,
+    ) -> Arc<Self> {
+        let this = Arc::new(Self {
+            socket,
+            options,
+            next_seq: Mutex::new(0),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            send_order: Mutex::new(()),
+            subscriptions: Arc::new(Mutex::new(HashMap::new())),
+        });
+
+        this.clone().spawn_recv_task();
+        this.clone().spawn_retransmit_task();
+
+        this
+    }
+
+    fn spawn_recv_task(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let len = match self.socket.recv(&mut buf).await {
+                    Ok(len) => len,
+                    Err(err) => {
+                        error!("reliable transport recv failed: {err}");
+                        continue;
+                    }
+                };
+                let Some((tag, id, payload)) = Self::parse_frame(&buf[..len]) else {
+                    continue;
+                };
+                match tag {
+                    FRAME_RESPONSE => self.handle_response_frame(id, payload).await,
+                    FRAME_STREAM => self.handle_stream_frame(id, payload).await,
+                    tag => error!("unknown frame type {tag}"),
+                }
+            }
+        });
+    }
+
+    /// Splits a raw datagram into its `(tag, id, payload)`, or `None` if
+    /// it's too short to hold the 1-byte tag and 4-byte big-endian id every
+    /// frame this transport sends is prefixed with.
+    fn parse_frame(buf: &[u8]) -> Option<(u8, u32, &[u8])> {
+        if buf.len() < 5 {
+            return None;
+        }
+        let id = u32::from_be_bytes(buf[1..5].try_into().unwrap());
+        Some((buf[0], id, &buf[5..]))
+    }
+
+    async fn handle_response_frame(&self, seq: u32, payload: &[u8]) {
+        let Some(in_flight) = self.in_flight.lock().await.remove(&seq) else {
+            // Duplicate or late response for an op we've already completed
+            // (or given up on) -- drop it.
+            return;
+        };
+        match bincode::deserialize::<GraphOpResponse>(payload) {
+            Ok(resp) => {
+                let _ = in_flight.reply.send(resp);
+            }
+            Err(err) => error!("failed to decode GraphOpResponse for op {seq}: {err}"),
+        }
+    }
+
+    async fn handle_stream_frame(&self, subscription_id: u32, payload: &[u8]) {
+        let subscriptions = self.subscriptions.lock().await;
+        let Some(sender) = subscriptions.get(&subscription_id) else {
+            // No (or no longer any) listener for this subscription -- drop it.
+            return;
+        };
+        match bincode::deserialize::<Measurement>(payload) {
+            Ok(measurement) => {
+                // An error just means every receiver for this meter is gone,
+                // so there is nobody left to read the value; it's dropped.
+                let _ = sender.send(measurement);
+            }
+            Err(err) => {
+                error!("failed to decode Measurement for subscription {subscription_id}: {err}")
+            }
+        }
+    }
+
+    fn spawn_retransmit_task(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                sleep(Duration::from_millis(10)).await;
+                let now = Instant::now();
+                let mut expired = Vec::new();
+                let mut in_flight = self.in_flight.lock().await;
+                for (seq, entry) in in_flight.iter_mut() {
+                    if now.duration_since(entry.sent_at) < entry.rto {
+                        continue;
+                    }
+                    if entry.retries >= self.options.max_retries {
+                        expired.push(*seq);
+                        continue;
+                    }
+                    entry.retries += 1;
+                    entry.rto *= 2;
+                    entry.sent_at = now;
+                    if let Err(err) = self.socket.send(&entry.datagram).await {
+                        error!("retransmit of op {seq} failed: {err}");
+                    }
+                }
+                for seq in expired {
+                    // The reply sender is dropped here, which completes the
+                    // waiting `send` with a RecvError we turn into a timeout.
+                    in_flight.remove(&seq);
+                }
+            }
+        });
+    }
+
+    /// Sends `op`, retransmitting until it's acknowledged or `max_retries`
+    /// is exhausted.
+    pub async fn send(&self, op: &GraphOp) -> Result<GraphOpResponse> {
+        let _order_guard = if self.options.ordered {
+            Some(self.send_order.lock().await)
+        } else {
+            None
+        };
+
+        let seq = {
+            let mut next_seq = self.next_seq.lock().await;
+            let seq = *next_seq;
+            *next_seq = next_seq.wrapping_add(1);
+            seq
+        };
+
+        let mut datagram = vec![FRAME_RESPONSE];
+        datagram.extend_from_slice(&seq.to_be_bytes());
+        datagram.extend_from_slice(&bincode::serialize(op)?);
+
+        let (reply, response) = oneshot::channel();
+        self.in_flight.lock().await.insert(
+            seq,
+            InFlight {
+                datagram: datagram.clone(),
+                sent_at: Instant::now(),
+                rto: self.options.initial_rto,
+                retries: 0,
+                reply,
+            },
+        );
+
+        self.socket.send(&datagram).await?;
+
+        response
+            .await
+            .map_err(|_| anyhow!("op {seq} ({op:?}) timed out after exhausting its retry budget"))
+    }
+
+    /// Registers interest in `subscription_id`'s measurement stream,
+    /// returning a receiver that always has the latest value pushed by the
+    /// server for that subscription.
+    pub async fn register_subscription(
+        &self,
+        subscription_id: u32,
+    ) -> watch::Receiver<Measurement> {
+        let (sender, receiver) = watch::channel(Measurement::default());
+        self.subscriptions
+            .lock()
+            .await
+            .insert(subscription_id, sender);
+        receiver
+    }
+
+    /// Forgets `subscription_id`, so a stray stream frame for it (one sent
+    /// before the server processes our `Unsubscribe`) is dropped instead of
+    /// being held onto forever. Pairs with `register_subscription`.
+    pub async fn unregister_subscription(&self, subscription_id: u32) {
+        self.subscriptions.lock().await.remove(&subscription_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_frame_rejects_short_buffers() {
+        assert_eq!(ReliableTransport::parse_frame(&[]), None);
+        assert_eq!(
+            ReliableTransport::parse_frame(&[FRAME_RESPONSE, 0, 0, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_frame_splits_tag_id_and_payload() {
+        let mut buf = vec![FRAME_STREAM];
+        buf.extend_from_slice(&42u32.to_be_bytes());
+        buf.extend_from_slice(b"payload");
+
+        let (tag, id, payload) = ReliableTransport::parse_frame(&buf).unwrap();
+        assert_eq!(tag, FRAME_STREAM);
+        assert_eq!(id, 42);
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn parse_frame_allows_an_empty_payload() {
+        let mut buf = vec![FRAME_RESPONSE];
+        buf.extend_from_slice(&0u32.to_be_bytes());
+
+        let (tag, id, payload) = ReliableTransport::parse_frame(&buf).unwrap();
+        assert_eq!(tag, FRAME_RESPONSE);
+        assert_eq!(id, 0);
+        assert!(payload.is_empty());
+    }
+}